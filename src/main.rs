@@ -1,9 +1,12 @@
 use std::{
+    collections::HashMap,
     fs,
-    io::{self, BufRead, BufReader, Write},
-    net::{TcpListener, TcpStream},
-    path::PathBuf,
-    sync::Arc,
+    io::{self, BufRead, BufReader, Read, Seek, Write},
+    net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs},
+    path::{Path, PathBuf},
+    sync::{mpsc, Arc, Mutex},
+    thread,
+    time::Duration,
 };
 
 use clap::Parser;
@@ -21,17 +24,83 @@ pub struct Args {
     /// suffix to append to requested file names
     #[arg(short, long, default_value = ".html")]
     suffix: String,
+
+    /// number of worker threads handling connections (defaults to available parallelism)
+    #[arg(short, long, default_value_t = default_workers())]
+    workers: usize,
+
+    /// TOML file of path-prefix routes served from other directories or proxied upstreams;
+    /// omit for zero-config static serving of `root`
+    #[arg(long)]
+    config: Option<PathBuf>,
+
+    /// origin allowed to make cross-origin requests (repeatable); pass `*` to allow any origin
+    #[arg(long = "cors-origin")]
+    cors_origins: Vec<String>,
+
+    #[arg(skip)]
+    routes: Vec<Route>,
+}
+
+/// A single `--config` rule: requests under `prefix` are either served from `dir` or
+/// reverse-proxied to `upstream` (`http://host:port`).
+#[derive(serde::Deserialize, Clone)]
+struct Route {
+    prefix: String,
+    #[serde(default)]
+    dir: Option<PathBuf>,
+    #[serde(default)]
+    upstream: Option<String>,
+}
+
+#[derive(serde::Deserialize, Default)]
+struct RoutesFile {
+    #[serde(default)]
+    routes: Vec<Route>,
+}
+
+fn load_routes(path: &Path) -> io::Result<Vec<Route>> {
+    let contents = fs::read_to_string(path)?;
+    let parsed: RoutesFile =
+        toml::from_str(&contents).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+    let mut routes = parsed.routes;
+    for route in &mut routes {
+        // canonicalized like `args.root`, since `serve_static` checks paths with `starts_with`
+        if let Some(dir) = &mut route.dir {
+            *dir = fs::canonicalize(&*dir)?;
+        }
+    }
+    Ok(routes)
 }
 
+fn default_workers() -> usize {
+    thread::available_parallelism().map(|n| n.get()).unwrap_or(4)
+}
+
+/// idle time to wait for the next request on a keep-alive connection before dropping it
+const IDLE_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// caps on a single request's headers, so a client that never sends the blank-line terminator
+/// can't grow `Request::headers` unboundedly; a line's length is checked once it has been read
+const MAX_HEADER_COUNT: usize = 100;
+const MAX_HEADER_LINE_LEN: usize = 8192;
+
+/// connect/read timeout for reverse-proxy upstreams, so a hung backend can't tie up a worker
+/// thread (and, transitively, the whole bounded thread pool) indefinitely
+const UPSTREAM_TIMEOUT: Duration = Duration::from_secs(10);
+
 struct Request {
     pub path: String,
     pub raw_path: String,
     pub method: String,
+    pub version: String,
+    pub headers: HashMap<String, String>,
 }
 
 struct Response {
     pub status_code: i32,
     pub status_message: String,
+    pub headers: Vec<(String, String)>,
     pub body: Vec<u8>,
 }
 
@@ -40,25 +109,112 @@ impl Response {
         Self {
             status_code,
             status_message: status_message.into(),
+            headers: Vec::new(),
             body,
         }
     }
 
-    pub fn write(&self, stream: &mut TcpStream) -> io::Result<()> {
-        stream.write_all(
-            format!(
-                "HTTP/1.1 {} {}\r\nContent-Length: {}\r\n\r\n",
-                self.status_code,
-                self.status_message,
-                self.body.len()
-            )
-            .as_bytes(),
-        )?;
+    /// Attaches an extra response header, returned for chaining onto `new`.
+    pub fn header(mut self, name: impl Into<String>, value: impl Into<String>) -> Self {
+        self.headers.push((name.into(), value.into()));
+        self
+    }
+
+    pub fn write(&self, stream: &mut TcpStream, keep_alive: bool) -> io::Result<()> {
+        let mut head = format!(
+            "HTTP/1.1 {} {}\r\nContent-Length: {}\r\nConnection: {}\r\n",
+            self.status_code,
+            self.status_message,
+            self.body.len(),
+            if keep_alive { "keep-alive" } else { "close" },
+        );
+        for (name, value) in &self.headers {
+            head.push_str(&format!("{name}: {value}\r\n"));
+        }
+        head.push_str("\r\n");
+
+        stream.write_all(head.as_bytes())?;
         stream.write_all(&self.body)?;
         stream.flush()
     }
 }
 
+type Job = Box<dyn FnOnce() + Send + 'static>;
+
+/// A fixed-size pool of worker threads fed by a bounded queue, so a burst of connections
+/// backs up the queue (blocking `execute`) instead of spawning unbounded OS threads.
+struct ThreadPool {
+    workers: Vec<Worker>,
+    sender: Option<mpsc::SyncSender<Job>>,
+}
+
+impl ThreadPool {
+    /// Capacity of the job queue beyond the worker count, before `execute` starts blocking.
+    const QUEUE_SLACK: usize = 16;
+
+    /// Panics in debug builds if `size` is 0; callers must validate user-supplied sizes
+    /// themselves (see the `--workers` check in `main`) rather than letting that reach here.
+    pub fn new(size: usize) -> Self {
+        debug_assert!(size > 0);
+
+        let (sender, receiver) = mpsc::sync_channel(size + Self::QUEUE_SLACK);
+        let receiver = Arc::new(Mutex::new(receiver));
+
+        let workers = (0..size)
+            .map(|id| Worker::new(id, Arc::clone(&receiver)))
+            .collect();
+
+        Self {
+            workers,
+            sender: Some(sender),
+        }
+    }
+
+    /// Queues `job` for a worker, blocking the caller if every worker is busy and the queue is full.
+    pub fn execute<F>(&self, job: F)
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        if let Some(sender) = &self.sender {
+            let _ = sender.send(Box::new(job));
+        }
+    }
+}
+
+impl Drop for ThreadPool {
+    fn drop(&mut self) {
+        drop(self.sender.take());
+        for worker in &mut self.workers {
+            if let Some(thread) = worker.thread.take() {
+                let _ = thread.join();
+            }
+        }
+    }
+}
+
+struct Worker {
+    #[allow(dead_code)]
+    id: usize,
+    thread: Option<thread::JoinHandle<()>>,
+}
+
+impl Worker {
+    fn new(id: usize, receiver: Arc<Mutex<mpsc::Receiver<Job>>>) -> Self {
+        let thread = thread::spawn(move || loop {
+            let job = match receiver.lock().unwrap().recv() {
+                Ok(job) => job,
+                Err(_) => break, // sender dropped: pool is shutting down
+            };
+            job();
+        });
+
+        Self {
+            id,
+            thread: Some(thread),
+        }
+    }
+}
+
 fn main() -> std::io::Result<()> {
     let _ = simplelog::TermLogger::init(
         if cfg!(debug_assertions) {
@@ -73,60 +229,103 @@ fn main() -> std::io::Result<()> {
 
     let mut args = Args::parse();
     args.root = fs::canonicalize(&args.root)?;
+    if let Some(config_path) = &args.config {
+        args.routes = load_routes(config_path)?;
+    }
+    if args.workers == 0 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "--workers must be at least 1",
+        ));
+    }
     let args = Arc::new(args);
 
     let listener = TcpListener::bind(&args.bind)?;
     info!("sigma-serve-rs started serving files on {}", args.bind);
 
+    let pool = ThreadPool::new(args.workers);
+
     loop {
-        let Ok((mut stream, addr)) = listener.accept() else {
+        let Ok((stream, addr)) = listener.accept() else {
             continue;
         };
 
         let args = Arc::clone(&args);
-        std::thread::spawn(move || {
-            let request = match parse_request(&mut stream) {
-                Ok(request) => request,
-                Err(e) => {
-                    match e.kind() {
-                        io::ErrorKind::ConnectionReset => return, // can also mean not a HTTP connection (not a relevant error so not logged)
-                        io::ErrorKind::InvalidData => return, // invalid UTF-8 (probably better to return a 400 Bad Request but eh)
-                        _ => {
-                            error!("request parser error: {e}");
-                            return;
-                        }
+        pool.execute(move || handle_connection(stream, addr, &args));
+    }
+}
+
+/// Serves requests off of a single connection until the client (or we) decide to close it,
+/// honoring HTTP/1.1 keep-alive so several requests can share one socket.
+fn handle_connection(stream: TcpStream, addr: SocketAddr, args: &Args) {
+    if let Err(e) = stream.set_read_timeout(Some(IDLE_TIMEOUT)) {
+        error!("failed to set read timeout: {e}");
+    }
+
+    let mut reader = BufReader::new(stream);
+
+    loop {
+        let request = match parse_request(&mut reader) {
+            Ok(request) => request,
+            Err(e) => {
+                match e.kind() {
+                    io::ErrorKind::ConnectionReset => {} // can also mean not a HTTP connection (not a relevant error so not logged)
+                    io::ErrorKind::InvalidData => {} // invalid UTF-8 (probably better to return a 400 Bad Request but eh)
+                    io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut => {} // idle keep-alive connection timed out
+                    io::ErrorKind::InvalidInput => {
+                        let response =
+                            Response::new(400, "Bad Request", "400 Bad Request".as_bytes().to_vec());
+                        let _ = response.write(reader.get_mut(), false);
                     }
+                    _ => error!("request parser error: {e}"),
                 }
-            };
+                return;
+            }
+        };
 
-            let response = match prepare_response(&request, &args) {
-                Ok(response) => response,
-                Err(e) => {
-                    error!("client handler error: {e}");
-                    Response::new(
-                        500,
-                        "Internal Server Error",
-                        "500 Internal Server Error".as_bytes().to_vec(),
-                    )
-                }
-            };
+        let response = match prepare_response(&request, args, addr) {
+            Ok(response) => response,
+            Err(e) => {
+                error!("client handler error: {e}");
+                Response::new(
+                    500,
+                    "Internal Server Error",
+                    "500 Internal Server Error".as_bytes().to_vec(),
+                )
+            }
+        };
 
-            info!(
-                "{:?} - \"{} {}\" - {}",
-                addr.ip(),
-                request.method,
-                request.raw_path,
-                response.status_code
-            );
+        info!(
+            "{:?} - \"{} {}\" - {}",
+            addr.ip(),
+            request.method,
+            request.raw_path,
+            response.status_code
+        );
 
-            let _ = response.write(&mut stream);
-        });
+        let keep_alive = wants_keep_alive(&request);
+        if response.write(reader.get_mut(), keep_alive).is_err() || !keep_alive {
+            return;
+        }
     }
 }
 
-fn parse_request(stream: &mut TcpStream) -> std::io::Result<Request> {
-    let buf_reader = BufReader::new(stream);
-    let request_line = match buf_reader.lines().next().transpose()? {
+/// Defaults to keep-alive for HTTP/1.1 and close for older versions, per RFC 7230 §6.3,
+/// unless the client overrides it with an explicit `Connection` header.
+fn wants_keep_alive(request: &Request) -> bool {
+    match request
+        .headers
+        .get("connection")
+        .map(|value| value.to_ascii_lowercase())
+    {
+        Some(value) if value.contains("close") => false,
+        Some(value) if value.contains("keep-alive") => true,
+        _ => request.version == "HTTP/1.1",
+    }
+}
+
+fn parse_request(reader: &mut BufReader<TcpStream>) -> std::io::Result<Request> {
+    let request_line = match reader.lines().next().transpose()? {
         Some(line) => line,
         None => return Err(io::ErrorKind::ConnectionReset.into()),
     };
@@ -134,6 +333,27 @@ fn parse_request(stream: &mut TcpStream) -> std::io::Result<Request> {
     let mut parts = request_line.split_whitespace();
     let method = parts.next().unwrap_or("");
     let path = parts.next().unwrap_or("/");
+    let version = parts.next().unwrap_or("HTTP/1.1").to_string();
+
+    let mut headers = HashMap::new();
+    let mut header_lines = 0usize;
+    while let Some(line) = reader.lines().next().transpose()? {
+        if line.is_empty() {
+            break;
+        }
+
+        header_lines += 1;
+        if header_lines > MAX_HEADER_COUNT || line.len() > MAX_HEADER_LINE_LEN {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "too many or too large request headers",
+            ));
+        }
+
+        if let Some((name, value)) = line.split_once(':') {
+            headers.insert(name.trim().to_ascii_lowercase(), value.trim().to_string());
+        }
+    }
 
     Ok(Request {
         path: urlencoding::decode(path)
@@ -141,10 +361,55 @@ fn parse_request(stream: &mut TcpStream) -> std::io::Result<Request> {
             .to_string(),
         raw_path: path.to_string(),
         method: method.to_string(),
+        version,
+        headers,
     })
 }
 
-fn prepare_response(request: &Request, args: &Args) -> std::io::Result<Response> {
+fn prepare_response(request: &Request, args: &Args, peer: SocketAddr) -> std::io::Result<Response> {
+    let response = dispatch_request(request, args, peer)?;
+    Ok(apply_cors(response, request, args))
+}
+
+/// Echoes back a matching `Origin` with `Access-Control-Allow-Origin` (never a blanket `*`, even
+/// when `*` is configured, so the header still works for credentialed requests) plus `Vary`.
+fn apply_cors(response: Response, request: &Request, args: &Args) -> Response {
+    let Some(origin) = request.headers.get("origin") else {
+        return response;
+    };
+
+    if !args
+        .cors_origins
+        .iter()
+        .any(|allowed| allowed == "*" || allowed == origin)
+    {
+        return response;
+    }
+
+    response
+        .header("Access-Control-Allow-Origin", origin.clone())
+        .header("Vary", "Origin")
+}
+
+/// Replies to a CORS preflight with the methods we support and whatever headers the browser asked
+/// to send, instead of the blanket `405` a plain `OPTIONS` request used to get.
+fn preflight_response(request: &Request) -> Response {
+    let response =
+        Response::new(204, "No Content", Vec::new()).header("Access-Control-Allow-Methods", "GET, OPTIONS");
+
+    match request.headers.get("access-control-request-headers") {
+        Some(requested_headers) => {
+            response.header("Access-Control-Allow-Headers", requested_headers.clone())
+        }
+        None => response,
+    }
+}
+
+fn dispatch_request(request: &Request, args: &Args, peer: SocketAddr) -> std::io::Result<Response> {
+    if request.method == "OPTIONS" {
+        return Ok(preflight_response(request));
+    }
+
     if request.method != "GET" {
         return Ok(Response::new(
             405,
@@ -153,42 +418,335 @@ fn prepare_response(request: &Request, args: &Args) -> std::io::Result<Response>
         ));
     }
 
-    let requested = if request.path == "/" {
+    if let Some(route) = match_route(&args.routes, &request.path) {
+        if let Some(upstream) = &route.upstream {
+            return proxy_request(request, upstream, peer);
+        }
+        if let Some(dir) = &route.dir {
+            let path = strip_route_prefix(&request.path, &route.prefix);
+            return serve_static(request, &path, dir, args);
+        }
+    }
+
+    serve_static(request, &request.path, &args.root, args)
+}
+
+/// Picks the most specific (longest-prefix) route matching `path`, if any are configured.
+fn match_route<'a>(routes: &'a [Route], path: &str) -> Option<&'a Route> {
+    routes
+        .iter()
+        .filter(|route| route_matches(path, &route.prefix))
+        .max_by_key(|route| route.prefix.len())
+}
+
+/// Whether `prefix` matches `path` on a path-segment boundary, so a rule for `/api` doesn't also
+/// swallow `/apikey` or `/apiv2`.
+fn route_matches(path: &str, prefix: &str) -> bool {
+    match path.strip_prefix(prefix) {
+        Some(rest) => rest.is_empty() || rest.starts_with('/') || prefix.ends_with('/'),
+        None => false,
+    }
+}
+
+/// Removes a route's prefix from `path`, keeping it rooted (`"/"` for an exact prefix match).
+fn strip_route_prefix(path: &str, prefix: &str) -> String {
+    let stripped = path.strip_prefix(prefix).unwrap_or(path);
+    if stripped.starts_with('/') {
+        stripped.to_string()
+    } else {
+        format!("/{stripped}")
+    }
+}
+
+/// Forwards a request to a reverse-proxy upstream (`http://host:port`), adding `X-Forwarded-For`
+/// so the upstream can see the original client, and relays back its status, its headers (minus
+/// `Content-Length`, `Connection` and `Transfer-Encoding`) and its `Content-Length` body; a
+/// chunked or length-less upstream response becomes a `502`.
+fn proxy_request(request: &Request, upstream: &str, peer: SocketAddr) -> std::io::Result<Response> {
+    let authority = upstream.strip_prefix("http://").unwrap_or(upstream);
+    let addr = authority.to_socket_addrs()?.next().ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("could not resolve upstream address {authority}"),
+        )
+    })?;
+    let mut conn = TcpStream::connect_timeout(&addr, UPSTREAM_TIMEOUT)?;
+    conn.set_read_timeout(Some(UPSTREAM_TIMEOUT))?;
+
+    let mut head = format!("{} {} HTTP/1.1\r\n", request.method, request.raw_path);
+    head.push_str(&format!("Host: {authority}\r\n"));
+    for (name, value) in &request.headers {
+        if name.eq_ignore_ascii_case("host") {
+            continue; // overridden above with the upstream's own host:port
+        }
+        head.push_str(&format!("{name}: {value}\r\n"));
+    }
+    head.push_str(&format!("X-Forwarded-For: {}\r\n\r\n", peer.ip()));
+    conn.write_all(head.as_bytes())?;
+
+    let mut reader = BufReader::new(conn);
+    let status_line = reader.lines().next().transpose()?.unwrap_or_default();
+    let mut parts = status_line.splitn(3, ' ');
+    let status_code = parts
+        .nth(1)
+        .and_then(|code| code.parse().ok())
+        .unwrap_or(502);
+    let status_message = parts.next().unwrap_or("Bad Gateway").to_string();
+
+    let mut headers = Vec::new();
+    let mut content_length = None;
+    let mut chunked = false;
+    while let Some(line) = reader.lines().next().transpose()? {
+        if line.is_empty() {
+            break;
+        }
+        let Some((name, value)) = line.split_once(':') else {
+            continue;
+        };
+        let (name, value) = (name.trim().to_string(), value.trim().to_string());
+        if name.eq_ignore_ascii_case("content-length") {
+            content_length = value.parse().ok();
+            continue; // Response::write emits its own Content-Length for the relayed body
+        }
+        if name.eq_ignore_ascii_case("connection") {
+            continue; // we manage keep-alive with our own client independently
+        }
+        if name.eq_ignore_ascii_case("transfer-encoding") {
+            chunked = value.to_ascii_lowercase().contains("chunked");
+            continue; // we don't decode chunked bodies; handled (and rejected) below
+        }
+        headers.push((name, value));
+    }
+
+    // We only relay a fully-buffered body with a known length, so a chunked upstream response
+    // (no Content-Length) can't be read correctly; fail loudly instead of returning an empty body.
+    if chunked {
+        return Ok(Response::new(
+            502,
+            "Bad Gateway",
+            "502 Bad Gateway: chunked upstream responses are not supported"
+                .as_bytes()
+                .to_vec(),
+        ));
+    }
+    let Some(content_length) = content_length else {
+        return Ok(Response::new(
+            502,
+            "Bad Gateway",
+            "502 Bad Gateway: upstream response had no Content-Length"
+                .as_bytes()
+                .to_vec(),
+        ));
+    };
+
+    let mut body = vec![0u8; content_length];
+    reader.read_exact(&mut body)?;
+
+    let mut response = Response::new(status_code, status_message, body);
+    for (name, value) in headers {
+        response = response.header(name, value);
+    }
+    Ok(response)
+}
+
+/// Serves a file out of `root` the same way plain static serving always has, with conditional
+/// GET and Range support; `root` is either `args.root` or a route's configured directory.
+fn serve_static(request: &Request, path: &str, root: &Path, args: &Args) -> std::io::Result<Response> {
+    let requested = if path == "/" {
         PathBuf::from("index.html")
     } else {
-        let decoded =
-            match urlencoding::decode(request.path.strip_prefix('/').unwrap_or(&request.path)) {
-                Ok(decoded) => decoded,
-                Err(_) => {
-                    return Ok(Response::new(
-                        400,
-                        "Bad Request",
-                        "400 Bad Request".as_bytes().to_vec(),
-                    ));
-                }
-            };
+        let decoded = match urlencoding::decode(path.strip_prefix('/').unwrap_or(path)) {
+            Ok(decoded) => decoded,
+            Err(_) => {
+                return Ok(Response::new(
+                    400,
+                    "Bad Request",
+                    "400 Bad Request".as_bytes().to_vec(),
+                ));
+            }
+        };
         PathBuf::from(format!("{}{}", decoded, args.suffix))
     };
 
-    let full_path = match fs::canonicalize(args.root.join(requested)) {
+    let full_path = match fs::canonicalize(root.join(requested)) {
         Ok(full_path) => full_path,
         Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(not_found(args)),
         Err(e) => return Err(e),
     };
 
-    if !full_path.starts_with(&args.root) {
+    if !full_path.starts_with(root) {
         return Ok(not_found(args));
     }
 
-    Ok(match fs::read(&full_path) {
-        Ok(contents) => Response::new(200, "Ok", contents),
-        Err(_) => not_found(args),
+    let metadata = fs::metadata(&full_path)?;
+    let modified = metadata.modified()?;
+    let last_modified = httpdate::fmt_http_date(modified);
+    let mtime_secs = modified
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    let etag = format!("W/\"{}-{}\"", metadata.len(), mtime_secs);
+    // HTTP dates only carry whole-second resolution, so compare against `modified` truncated to
+    // match what the client actually received in a prior `Last-Modified` (and what it can send
+    // back in `If-Modified-Since`/`If-Range`), rather than against the raw sub-second mtime.
+    let modified_secs = std::time::UNIX_EPOCH + Duration::from_secs(mtime_secs);
+
+    if is_not_modified(request, &etag, modified_secs) {
+        return Ok(Response::new(304, "Not Modified", Vec::new())
+            .header("Last-Modified", last_modified)
+            .header("ETag", etag));
+    }
+
+    let content_type = content_type(&full_path, args);
+    let total = metadata.len();
+
+    let range = request
+        .headers
+        .get("range")
+        .filter(|_| if_range_satisfied(request, &etag, modified_secs))
+        .map(|value| parse_range(value, total));
+
+    Ok(match range {
+        Some(RangeResult::Satisfiable(start, end)) => {
+            match read_range(&full_path, start, end) {
+                Ok(slice) => Response::new(206, "Partial Content", slice)
+                    .header("Content-Type", content_type)
+                    .header("Last-Modified", last_modified)
+                    .header("ETag", etag)
+                    .header("Accept-Ranges", "bytes")
+                    .header("Content-Range", format!("bytes {start}-{end}/{total}")),
+                Err(_) => not_found(args),
+            }
+        }
+        Some(RangeResult::Unsatisfiable) => {
+            Response::new(416, "Range Not Satisfiable", Vec::new())
+                .header("Content-Range", format!("bytes */{total}"))
+                .header("Accept-Ranges", "bytes")
+        }
+        Some(RangeResult::Full) | None => match fs::read(&full_path) {
+            Ok(contents) => Response::new(200, "Ok", contents)
+                .header("Content-Type", content_type)
+                .header("Last-Modified", last_modified)
+                .header("ETag", etag)
+                .header("Accept-Ranges", "bytes"),
+            Err(_) => not_found(args),
+        },
     })
 }
 
+/// Reads only the inclusive `[start, end]` byte range of `path`, instead of buffering the whole
+/// file, so serving a chunk of a large (e.g. video) file doesn't require loading it all into RAM.
+fn read_range(path: &Path, start: u64, end: u64) -> io::Result<Vec<u8>> {
+    let mut file = fs::File::open(path)?;
+    file.seek(io::SeekFrom::Start(start))?;
+
+    let mut slice = vec![0u8; (end - start + 1) as usize];
+    file.read_exact(&mut slice)?;
+    Ok(slice)
+}
+
+/// Whether a `Range` header should be honored given an `If-Range` validator; with no `If-Range`
+/// present a `Range` header always applies.
+fn if_range_satisfied(request: &Request, etag: &str, modified: std::time::SystemTime) -> bool {
+    match request.headers.get("if-range") {
+        None => true,
+        Some(validator) if validator == etag => true,
+        Some(validator) => httpdate::parse_http_date(validator)
+            .map(|since| modified <= since)
+            .unwrap_or(false),
+    }
+}
+
+enum RangeResult {
+    /// no (usable) range requested; serve the whole body
+    Full,
+    /// an inclusive `[start, end]` byte range within the file
+    Satisfiable(u64, u64),
+    /// the range selects no bytes of the file: its start lies beyond the end of the file, its
+    /// end precedes its start, it is a zero-length suffix, or the file is empty
+    Unsatisfiable,
+}
+
+/// Parses a `Range: bytes=...` header value, supporting `start-end`, `start-`, and `-suffixlen`.
+/// Malformed or multi-range headers fall back to `Full` per the "ignore if unparseable" guidance
+/// in RFC 7233 §3.1; only a syntactically valid but out-of-bounds range is `Unsatisfiable`.
+fn parse_range(value: &str, total: u64) -> RangeResult {
+    let Some(spec) = value.strip_prefix("bytes=") else {
+        return RangeResult::Full;
+    };
+
+    if spec.contains(',') {
+        return RangeResult::Full;
+    }
+
+    let Some((start_str, end_str)) = spec.split_once('-') else {
+        return RangeResult::Full;
+    };
+
+    let (start, end) = if start_str.is_empty() {
+        if end_str.is_empty() {
+            return RangeResult::Full;
+        }
+        match end_str.parse::<u64>() {
+            Ok(0) => return RangeResult::Unsatisfiable,
+            Ok(_) if total == 0 => return RangeResult::Unsatisfiable,
+            Ok(suffix_len) => (total.saturating_sub(suffix_len), total - 1),
+            Err(_) => return RangeResult::Full,
+        }
+    } else {
+        // with an empty file `start >= total` always holds, so these ranges end up Unsatisfiable
+        match start_str.parse::<u64>() {
+            Ok(start) if end_str.is_empty() => (start, total.saturating_sub(1)),
+            Ok(start) => match end_str.parse::<u64>() {
+                Ok(end) => (start, end.min(total.saturating_sub(1))),
+                Err(_) => return RangeResult::Full,
+            },
+            Err(_) => return RangeResult::Full,
+        }
+    };
+
+    if start > end || start >= total {
+        RangeResult::Unsatisfiable
+    } else {
+        RangeResult::Satisfiable(start, end)
+    }
+}
+
+/// `If-None-Match` takes precedence over `If-Modified-Since` per RFC 7232 §3.3.
+fn is_not_modified(request: &Request, etag: &str, modified: std::time::SystemTime) -> bool {
+    if let Some(if_none_match) = request.headers.get("if-none-match") {
+        return if_none_match
+            .split(',')
+            .any(|candidate| candidate.trim() == etag);
+    }
+
+    if let Some(if_modified_since) = request.headers.get("if-modified-since") {
+        if let Ok(since) = httpdate::parse_http_date(if_modified_since) {
+            return modified <= since;
+        }
+    }
+
+    false
+}
+
+/// Looks up the MIME type for a resolved file path, special-casing the pages we reach by
+/// appending `args.suffix` (typically `.html`) so they get a charset instead of bare `text/html`.
+fn content_type(full_path: &Path, args: &Args) -> String {
+    if !args.suffix.is_empty() && full_path.to_string_lossy().ends_with(args.suffix.as_str()) {
+        "text/html; charset=utf-8".to_string()
+    } else {
+        mime_guess::from_path(full_path)
+            .first_or_octet_stream()
+            .to_string()
+    }
+}
+
 fn not_found(args: &Args) -> Response {
     let fallback_path = args.root.join("404.html");
-    let fallback =
-        fs::read_to_string(&fallback_path).unwrap_or_else(|_| "404 Not Found".to_string());
-    return Response::new(404, "Not Found", fallback.as_bytes().to_vec());
+    match fs::read_to_string(&fallback_path) {
+        Ok(body) => Response::new(404, "Not Found", body.into_bytes())
+            .header("Content-Type", "text/html; charset=utf-8"),
+        Err(_) => Response::new(404, "Not Found", "404 Not Found".as_bytes().to_vec())
+            .header("Content-Type", "text/plain; charset=utf-8"),
+    }
 }